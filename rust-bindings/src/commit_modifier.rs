@@ -0,0 +1,23 @@
+use crate::{CommitModifier, SePolicy};
+use glib::translate::*;
+
+impl CommitModifier {
+    /// Attaches `sepolicy` to this modifier, so every file object written while
+    /// committing gets its `security.selinux` xattr computed via
+    /// [`SePolicy::label`].
+    ///
+    /// This wraps `ostree_repo_commit_modifier_set_sepolicy`, through which the
+    /// C implementation in `ostree-repo-commit.c` labels committed files: callers
+    /// get one commit operation that's guaranteed to store xattrs matching the
+    /// policy, instead of having to label a checkout by hand and then commit it
+    /// with no knowledge of whether the two stayed in sync.
+    #[doc(alias = "ostree_repo_commit_modifier_set_sepolicy")]
+    pub fn set_sepolicy(&self, sepolicy: &SePolicy) {
+        unsafe {
+            ffi::ostree_repo_commit_modifier_set_sepolicy(
+                self.to_glib_none().0,
+                sepolicy.to_glib_none().0,
+            );
+        }
+    }
+}
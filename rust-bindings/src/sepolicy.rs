@@ -0,0 +1,418 @@
+use crate::SePolicy;
+use crate::SePolicyRestoreconFlags;
+use gio::prelude::*;
+use glib::object::IsA;
+use glib::translate::*;
+use glib::GString;
+use std::fmt;
+use std::marker::PhantomData;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+
+/// GIO attribute holding a file's SELinux context (its `security.selinux`
+/// xattr), used to read a file's current SELinux label before relabeling it.
+const XATTR_SELINUX: &str = "selinux::context";
+
+/// Whether an [`FsCreateConGuard`] is currently held, anywhere in this process.
+///
+/// `ostree_sepolicy_setfscreatecon` is process-global kernel state (it survives
+/// across threads, since it's really just `setfscreatecon(3)` on the calling
+/// thread's credentials as seen by every other thread's file creation through
+/// shared process state), so this flag must be too -- a thread-local would let
+/// two threads each believe they hold the only guard.
+static FSCREATECON_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Returns whether an [`FsCreateConGuard`] is outstanding anywhere in the process.
+///
+/// Shared with [`SePolicy::acquire_shared`], which must refuse to construct a
+/// distinct policy instance while a previous one's fscreate context is still set.
+pub(crate) fn fscreatecon_guard_active() -> bool {
+    FSCREATECON_ACTIVE.load(Ordering::SeqCst)
+}
+
+// `SePolicy` is only `Send` (see auto/se_policy.rs), not `Sync`, so `Weak<SePolicy>`
+// is itself neither `Send` nor `Sync` (`Weak<T>`'s impls require `T: Send + Sync`).
+// A bare `Mutex<Weak<SePolicy>>` would therefore not be `Sync` either, and couldn't
+// live in a `static`. Storing `Arc<Mutex<SePolicy>>` instead sidesteps this: a
+// `Mutex<T>` is `Sync` whenever `T: Send`, so `Mutex<SePolicy>` is `Send + Sync`
+// regardless of `SePolicy`'s own `Sync`-ness, and the registry can hold a `Weak`
+// reference to it. This also gives callers of `SePolicy::current()` a handle they
+// can actually share across threads, which a bare `Arc<SePolicy>` could not.
+fn registry() -> &'static Mutex<Weak<Mutex<SePolicy>>> {
+    static REGISTRY: OnceLock<Mutex<Weak<Mutex<SePolicy>>>> = OnceLock::new();
+    REGISTRY.get_or_init(|| Mutex::new(Weak::new()))
+}
+
+/// Errors produced by the process-wide [`SePolicy`] registry.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum SePolicyError {
+    /// A distinct `SePolicy` instance is already active in this process while an
+    /// [`FsCreateConGuard`] is still outstanding, so constructing a new one would
+    /// risk labeling files under the wrong policy.
+    AlreadyActive,
+    /// The underlying `ostree_sepolicy_new*` constructor failed.
+    Glib(glib::Error),
+}
+
+impl fmt::Display for SePolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SePolicyError::AlreadyActive => f.write_str(
+                "a distinct OstreeSePolicy is already active in this process \
+                 while an fscreate context is outstanding",
+            ),
+            SePolicyError::Glib(e) => fmt::Display::fmt(e, f),
+        }
+    }
+}
+
+impl std::error::Error for SePolicyError {}
+
+impl From<glib::Error> for SePolicyError {
+    fn from(e: glib::Error) -> Self {
+        SePolicyError::Glib(e)
+    }
+}
+
+/// A scoped guard that resets the process' SELinux "fscreate" context when dropped.
+///
+/// `ostree_sepolicy_setfscreatecon` changes a process-global piece of state: every
+/// file created afterwards (by any thread) inherits the label until it is explicitly
+/// cleared again. This guard pairs the set with the matching
+/// `ostree_sepolicy_fscreatecon_cleanup` call so callers get a "create these files
+/// under this label, then restore the default" pattern instead of having to remember
+/// to clean up manually.
+///
+/// Only one guard may be outstanding at a time *in the whole process*, matching the
+/// single process-global slot it wraps: a second guard, even on another thread,
+/// would silently clobber the first one's cleanup. [`SePolicy::set_fscreatecon_guarded`]
+/// enforces this with a process-wide flag, and the guard is `!Send` so it is at
+/// least dropped on the thread that created it.
+#[must_use = "the fscreate context is reset when this guard is dropped"]
+pub struct FsCreateConGuard {
+    // Keeps this type `!Send`, so the guard can't be handed to another thread and
+    // dropped there behind the creating thread's back.
+    _not_send: PhantomData<*mut ()>,
+}
+
+impl Drop for FsCreateConGuard {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::ostree_sepolicy_fscreatecon_cleanup(ptr::null_mut());
+        }
+        let was_active = FSCREATECON_ACTIVE.swap(false, Ordering::SeqCst);
+        debug_assert!(was_active, "FsCreateConGuard dropped without a matching set");
+    }
+}
+
+impl SePolicy {
+    /// Sets the fscreate context for `path`/`mode`, returning a guard that restores
+    /// the default context when dropped.
+    ///
+    /// This is the guarded counterpart to [`SePolicy::setfscreatecon`]: use it
+    /// around the file creation (checkout, commit staging, etc.) that needs the
+    /// label, so the process-global context can never leak past the scope that
+    /// requested it. Only one guard may be live per process at a time (across all
+    /// threads); attempting to create a second while the first is still held
+    /// returns an error rather than silently clobbering the first guard's cleanup.
+    pub fn set_fscreatecon_guarded(
+        &self,
+        path: &str,
+        mode: u32,
+    ) -> Result<FsCreateConGuard, glib::Error> {
+        if FSCREATECON_ACTIVE
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            return Err(glib::Error::new(
+                glib::FileError::Busy,
+                "an FsCreateConGuard is already active in this process",
+            ));
+        }
+
+        if let Err(e) = self.setfscreatecon(path, mode) {
+            FSCREATECON_ACTIVE.store(false, Ordering::SeqCst);
+            return Err(e);
+        }
+
+        Ok(FsCreateConGuard {
+            _not_send: PhantomData,
+        })
+    }
+}
+
+/// Counts produced by [`SePolicy::relabel_tree`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RelabelStats {
+    /// Number of entries whose label was changed by `restorecon`.
+    pub relabeled: u64,
+    /// Number of entries that already carried the correct label.
+    pub unchanged: u64,
+}
+
+impl SePolicy {
+    /// Recursively applies `restorecon` to `root` and every entry beneath it.
+    ///
+    /// A SELinux policy only makes sense relative to a particular deployment or
+    /// commit root, so unlike [`SePolicy::restorecon`] (which labels a single path),
+    /// this walks the whole checked-out tree depth-first and relabels it in one
+    /// pass -- the operation you actually want after swapping in a new policy.
+    /// `root` itself is relabeled first, since a policy swap can just as well
+    /// change the root's own context as any of its children's.
+    ///
+    /// `progress`, if given, is called with the relative path (`""` for `root`
+    /// itself) and the label just applied to it; entries the policy assigns no
+    /// context to (e.g. under `ALLOW_NOLABEL`) are skipped and counted as
+    /// unchanged rather than reported. The walk honors `cancellable` between
+    /// entries and follows `flags`' `KEEP_EXISTING` semantics by deferring to
+    /// [`SePolicy::restorecon`], which already implements that check; symlinks
+    /// are labeled as themselves and never followed.
+    pub fn relabel_tree(
+        &self,
+        root: &impl IsA<gio::File>,
+        flags: SePolicyRestoreconFlags,
+        mut progress: Option<impl FnMut(&str, &GString)>,
+        cancellable: Option<&impl IsA<gio::Cancellable>>,
+    ) -> Result<RelabelStats, glib::Error> {
+        let root = root.as_ref();
+        let mut stats = RelabelStats::default();
+
+        let root_info = root.query_info(
+            &format!(
+                "{},{},{}",
+                gio::FILE_ATTRIBUTE_STANDARD_TYPE,
+                gio::FILE_ATTRIBUTE_UNIX_MODE,
+                XATTR_SELINUX,
+            ),
+            gio::FileQueryInfoFlags::NOFOLLOW_SYMLINKS,
+            cancellable,
+        )?;
+        self.relabel_one(root, &root_info, "", flags, cancellable, &mut stats, &mut progress)?;
+
+        self.relabel_tree_recurse(root, root, flags, &mut progress, cancellable, &mut stats)?;
+        Ok(stats)
+    }
+
+    /// Relabels a single already-enumerated entry and accounts for it in `stats`.
+    fn relabel_one(
+        &self,
+        target: &gio::File,
+        info: &gio::FileInfo,
+        relpath: &str,
+        flags: SePolicyRestoreconFlags,
+        cancellable: Option<&impl IsA<gio::Cancellable>>,
+        stats: &mut RelabelStats,
+        progress: &mut Option<impl FnMut(&str, &GString)>,
+    ) -> Result<(), glib::Error> {
+        // SELinux file-context matching is on absolute paths, so `relpath`
+        // (which `gio::File::relative_path` returns with no leading `/`) needs
+        // one prepended before it's meaningful to `restorecon`.
+        let abspath = format!("/{relpath}");
+
+        // The label the policy computes for this path is the same before and
+        // after `restorecon`, so comparing two policy computations can never
+        // detect a real change. Compare the on-disk xattr captured before
+        // `restorecon` ran against the label it actually applied instead.
+        let label_before = info.attribute_as_string(XATTR_SELINUX);
+        let new_label = match self.restorecon_opt(&abspath, Some(info), target, flags, cancellable)? {
+            Some(new_label) => new_label,
+            // libostree leaves the label unset (e.g. under `ALLOW_NOLABEL`)
+            // rather than assigning one; nothing changed on disk either way.
+            None => {
+                stats.unchanged += 1;
+                return Ok(());
+            }
+        };
+
+        if label_before.as_deref() == Some(new_label.as_str()) {
+            stats.unchanged += 1;
+        } else {
+            stats.relabeled += 1;
+        }
+        if let Some(cb) = progress.as_mut() {
+            cb(relpath, &new_label);
+        }
+
+        Ok(())
+    }
+
+    /// Like [`SePolicy::restorecon`], but returns `None` instead of panicking
+    /// when libostree leaves `out_new_label` unset (e.g. under `ALLOW_NOLABEL`).
+    /// The generated [`SePolicy::restorecon`] assumes the out-param is always
+    /// non-null on success and panics via `from_glib_full` otherwise.
+    fn restorecon_opt(
+        &self,
+        path: &str,
+        info: Option<&gio::FileInfo>,
+        target: &impl IsA<gio::File>,
+        flags: SePolicyRestoreconFlags,
+        cancellable: Option<&impl IsA<gio::Cancellable>>,
+    ) -> Result<Option<GString>, glib::Error> {
+        unsafe {
+            let mut out_new_label = ptr::null_mut();
+            let mut error = ptr::null_mut();
+            let is_ok = ffi::ostree_sepolicy_restorecon(
+                self.to_glib_none().0,
+                path.to_glib_none().0,
+                info.to_glib_none().0,
+                target.as_ref().to_glib_none().0,
+                flags.into_glib(),
+                &mut out_new_label,
+                cancellable.map(|p| p.as_ref()).to_glib_none().0,
+                &mut error,
+            );
+            assert_eq!(is_ok == glib::ffi::GFALSE, !error.is_null());
+            if error.is_null() {
+                Ok(if out_new_label.is_null() {
+                    None
+                } else {
+                    Some(from_glib_full(out_new_label))
+                })
+            } else {
+                Err(from_glib_full(error))
+            }
+        }
+    }
+
+    fn relabel_tree_recurse(
+        &self,
+        root: &gio::File,
+        dir: &gio::File,
+        flags: SePolicyRestoreconFlags,
+        progress: &mut Option<impl FnMut(&str, &GString)>,
+        cancellable: Option<&impl IsA<gio::Cancellable>>,
+        stats: &mut RelabelStats,
+    ) -> Result<(), glib::Error> {
+        let attrs = format!(
+            "{},{},{},{}",
+            gio::FILE_ATTRIBUTE_STANDARD_NAME,
+            gio::FILE_ATTRIBUTE_STANDARD_TYPE,
+            gio::FILE_ATTRIBUTE_UNIX_MODE,
+            XATTR_SELINUX,
+        );
+        let enumerator = dir.enumerate_children(
+            &attrs,
+            gio::FileQueryInfoFlags::NOFOLLOW_SYMLINKS,
+            cancellable,
+        )?;
+
+        loop {
+            if let Some(c) = cancellable {
+                if c.is_cancelled() {
+                    return Err(glib::Error::new(
+                        gio::IOErrorEnum::Cancelled,
+                        "relabel_tree was cancelled",
+                    ));
+                }
+            }
+
+            let info = match enumerator.next_file(cancellable) {
+                Ok(Some(info)) => info,
+                Ok(None) => break,
+                Err(e) => return Err(e),
+            };
+
+            let child = enumerator.child(&info);
+            let relpath = root
+                .relative_path(&child)
+                .map(|p| p.to_string_lossy().into_owned())
+                .unwrap_or_else(|| info.name().to_string_lossy().into_owned());
+
+            self.relabel_one(&child, &info, &relpath, flags, cancellable, stats, progress)?;
+
+            if info.file_type() == gio::FileType::Directory {
+                self.relabel_tree_recurse(root, &child, flags, progress, cancellable, stats)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl SePolicy {
+    /// Returns the currently active process-wide `SePolicy`, if one was obtained
+    /// through [`SePolicy::new_shared`], [`SePolicy::new_at_shared`], or
+    /// [`SePolicy::from_commit_shared`] and is still alive.
+    ///
+    /// libostree only supports one `OstreeSePolicy` instance being meaningfully
+    /// active per process; this lets code that needs a policy check for one
+    /// already in use instead of constructing a conflicting second instance.
+    ///
+    /// # Limitations
+    ///
+    /// This registry is opt-in: it only knows about policies constructed through
+    /// the `*_shared` methods below. [`SePolicy::new`], [`SePolicy::new_at`], and
+    /// [`SePolicy::from_commit`] remain ordinary gir-generated constructors that
+    /// hand out independent, unregistered instances with no checks at all --
+    /// calling them directly still lets a process end up with two distinct
+    /// `SePolicy` objects live at once, in violation of libostree's own
+    /// single-instance assumption. Prefer the `*_shared` constructors everywhere
+    /// the single-instance invariant matters; the registry cannot enforce it
+    /// against code that bypasses them.
+    ///
+    /// The returned handle is an `Arc<Mutex<SePolicy>>` rather than a bare
+    /// `Arc<SePolicy>`: `SePolicy` itself is `Send` but not `Sync`, so only the
+    /// `Mutex` makes this a handle that libraries can actually move to and share
+    /// across threads. Lock it to call methods on the policy.
+    pub fn current() -> Option<Arc<Mutex<SePolicy>>> {
+        registry().lock().unwrap().upgrade()
+    }
+
+    /// Like [`SePolicy::new`], but shares a single process-wide instance.
+    ///
+    /// If a `SePolicy` obtained through one of the `*_shared` constructors is
+    /// still alive, that instance is returned again rather than creating a
+    /// second, independent one. If none is alive but an [`FsCreateConGuard`] from
+    /// a previous instance is still outstanding, [`SePolicyError::AlreadyActive`]
+    /// is returned instead of constructing a policy that could race with that
+    /// guard's cleanup.
+    ///
+    /// See [`SePolicy::current`] for why this guarantee doesn't extend to callers
+    /// of the plain [`SePolicy::new`] constructor, and for why the returned handle
+    /// is wrapped in a `Mutex`.
+    pub fn new_shared(
+        path: &impl IsA<gio::File>,
+        cancellable: Option<&impl IsA<gio::Cancellable>>,
+    ) -> Result<Arc<Mutex<SePolicy>>, SePolicyError> {
+        Self::acquire_shared(|| SePolicy::new(path, cancellable))
+    }
+
+    /// Like [`SePolicy::new_at`], but shares a single process-wide instance. See
+    /// [`SePolicy::new_shared`] for the sharing semantics.
+    #[cfg(any(feature = "v2017_4", feature = "dox"))]
+    #[cfg_attr(feature = "dox", doc(cfg(feature = "v2017_4")))]
+    pub fn new_at_shared(
+        rootfs_dfd: i32,
+        cancellable: Option<&impl IsA<gio::Cancellable>>,
+    ) -> Result<Arc<Mutex<SePolicy>>, SePolicyError> {
+        Self::acquire_shared(|| SePolicy::new_at(rootfs_dfd, cancellable))
+    }
+
+    /// Like [`SePolicy::from_commit`], but shares a single process-wide instance.
+    /// See [`SePolicy::new_shared`] for the sharing semantics.
+    pub fn from_commit_shared(
+        repo: &crate::Repo,
+        rev: &str,
+        cancellable: Option<&impl IsA<gio::Cancellable>>,
+    ) -> Result<Arc<Mutex<SePolicy>>, SePolicyError> {
+        Self::acquire_shared(|| SePolicy::from_commit(repo, rev, cancellable))
+    }
+
+    fn acquire_shared(
+        construct: impl FnOnce() -> Result<SePolicy, glib::Error>,
+    ) -> Result<Arc<Mutex<SePolicy>>, SePolicyError> {
+        let mut slot = registry().lock().unwrap();
+        if let Some(existing) = slot.upgrade() {
+            return Ok(existing);
+        }
+        if fscreatecon_guard_active() {
+            return Err(SePolicyError::AlreadyActive);
+        }
+
+        let policy = Arc::new(Mutex::new(construct()?));
+        *slot = Arc::downgrade(&policy);
+        Ok(policy)
+    }
+}